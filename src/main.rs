@@ -1,14 +1,22 @@
 use axum::{
     extract::{Extension, Json, Query},
+    http::StatusCode,
+    response::IntoResponse,
     routing::{get, post},
     Router,
 };
-use chrono::{DateTime, Weekday};
+use chrono::{DateTime, Duration, Timelike, Utc, Weekday};
 use reqwest;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqlitePool;
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokio;
+use tokio::sync::Semaphore;
+
+// Worker-pool sizing and retry policy for the durable task queue.
+const WORKER_POOL_SIZE: usize = 4;
+const MAX_ATTEMPTS: i64 = 8;
+const POLL_INTERVAL_SECS: u64 = 10;
 
 #[derive(Debug, Deserialize)]
 struct StravaEvent {
@@ -33,15 +41,515 @@ struct Activity {
     // Additional fields can be added as needed
 }
 
+// Response body from Strava's OAuth token endpoint.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_at: i64, // Unix timestamp (seconds) when the access token expires.
+}
+
+/// A latitude/longitude bounding box used by a rule's geofence matcher.
+#[derive(Debug, Clone, Deserialize)]
+struct BoundingBox {
+    min_lat: f64,
+    max_lat: f64,
+    min_lng: f64,
+    max_lng: f64,
+}
+
+/// Conditions an activity must meet for a rule to fire. An omitted `weekdays`
+/// list or `bbox` means that dimension is not constrained.
+#[derive(Debug, Clone, Deserialize)]
+struct RuleMatcher {
+    activity_type: String,
+    #[serde(default)]
+    weekdays: Vec<String>, // e.g. ["Mon", "Tue", ...]; empty means any day.
+    #[serde(default)]
+    bbox: Option<BoundingBox>,
+}
+
+/// What to do to an activity that matches a rule.
+#[derive(Debug, Clone, Deserialize)]
+struct RuleAction {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    private: Option<bool>,
+}
+
+/// A single matcher/action pair. The first rule whose matcher accepts an
+/// activity is applied.
+#[derive(Debug, Clone, Deserialize)]
+struct Rule {
+    #[serde(rename = "match")]
+    matcher: RuleMatcher,
+    action: RuleAction,
+}
+
+impl Rule {
+    /// Whether this rule's matcher accepts the given activity.
+    fn matches(&self, activity_type: &str, weekday: Weekday, latlng: Option<(f64, f64)>) -> bool {
+        let m = &self.matcher;
+        if m.activity_type.to_lowercase() != activity_type.to_lowercase() {
+            return false;
+        }
+        if !m.weekdays.is_empty()
+            && !m
+                .weekdays
+                .iter()
+                .filter_map(|d| parse_weekday(d))
+                .any(|d| d == weekday)
+        {
+            return false;
+        }
+        if let Some(bbox) = &m.bbox {
+            match latlng {
+                Some((lat, lng)) => {
+                    if !(lat >= bbox.min_lat
+                        && lat <= bbox.max_lat
+                        && lng >= bbox.min_lng
+                        && lng <= bbox.max_lng)
+                    {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Parse a (case-insensitive, prefix) weekday name into a `chrono::Weekday`.
+fn parse_weekday(name: &str) -> Option<Weekday> {
+    match name.to_lowercase().as_str() {
+        s if s.starts_with("mon") => Some(Weekday::Mon),
+        s if s.starts_with("tue") => Some(Weekday::Tue),
+        s if s.starts_with("wed") => Some(Weekday::Wed),
+        s if s.starts_with("thu") => Some(Weekday::Thu),
+        s if s.starts_with("fri") => Some(Weekday::Fri),
+        s if s.starts_with("sat") => Some(Weekday::Sat),
+        s if s.starts_with("sun") => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+// A rules file is a TOML document with a top-level `rules` array.
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// Runtime configuration, loaded once at startup and shared via `Extension`.
+///
+/// The scalar settings come from the environment; the rule list is read from
+/// the TOML file named by `RULES_PATH`, falling back to the historical
+/// weekday-walk-in-NYC rule when no file is provided.
+#[derive(Debug, Clone)]
+struct Config {
+    client_id: String,
+    client_secret: String,
+    verify_token: String,
+    bind_addr: SocketAddr,
+    database_url: String,
+    rules: Vec<Rule>,
+}
+
+impl Config {
+    fn load() -> Result<Config, Box<dyn std::error::Error>> {
+        let client_id = std::env::var("STRAVA_CLIENT_ID")?;
+        let client_secret = std::env::var("STRAVA_CLIENT_SECRET")?;
+        let verify_token = std::env::var("STRAVA_VERIFY_TOKEN")?;
+        let bind_addr = std::env::var("BIND_ADDR")
+            .unwrap_or_else(|_| "0.0.0.0:3000".to_string())
+            .parse()?;
+        let database_url = std::env::var("DATABASE_URL")
+            .unwrap_or_else(|_| "sqlite://processed_activities.db".to_string());
+        let rules = match std::env::var("RULES_PATH") {
+            Ok(path) => {
+                let contents = std::fs::read_to_string(&path)?;
+                toml::from_str::<RulesFile>(&contents)?.rules
+            }
+            Err(_) => default_rules(),
+        };
+        Ok(Config {
+            client_id,
+            client_secret,
+            verify_token,
+            bind_addr,
+            database_url,
+            rules,
+        })
+    }
+}
+
+/// The built-in rule, equivalent to the original hardcoded behaviour: rename a
+/// weekday walk inside the NYC geofence to "Rusty" and make it private.
+fn default_rules() -> Vec<Rule> {
+    vec![Rule {
+        matcher: RuleMatcher {
+            activity_type: "walk".to_string(),
+            weekdays: ["Mon", "Tue", "Wed", "Thu", "Fri"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            bbox: Some(BoundingBox {
+                min_lat: 40.0,
+                max_lat: 41.0,
+                min_lng: -74.0,
+                max_lng: -73.0,
+            }),
+        },
+        action: RuleAction {
+            name: Some("Rusty".to_string()),
+            private: Some(true),
+        },
+    }]
+}
+
+/// Return a currently-valid access token for the given athlete.
+///
+/// Tokens are stored per-athlete in the `strava_tokens` table. Strava's
+/// access tokens are short-lived (~6 hours), so if the stored token is within
+/// 60 seconds of expiry we refresh it against the OAuth endpoint and persist
+/// the new credentials before handing back the fresh access token.
+async fn get_valid_token(
+    pool: &SqlitePool,
+    config: &Config,
+    owner_id: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let rec = sqlx::query!(
+        "SELECT access_token, refresh_token, expires_at FROM strava_tokens WHERE owner_id = ?",
+        owner_id as i64
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| format!("No stored token for athlete {}", owner_id))?;
+
+    // Return the stored token unless it's about to expire.
+    let expires_at = DateTime::from_timestamp(rec.expires_at, 0)
+        .ok_or("Stored expires_at is out of range")?;
+    if expires_at - Utc::now() > Duration::seconds(60) {
+        return Ok(rec.access_token);
+    }
+
+    // Refresh against Strava's OAuth endpoint.
+    let client = reqwest::Client::new();
+    let res = client
+        .post("https://www.strava.com/oauth/token")
+        .form(&[
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", rec.refresh_token.as_str()),
+        ])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(Box::new(strava_error(res).await));
+    }
+    let token: TokenResponse = res.json().await?;
+
+    // Persist the refreshed credentials for next time.
+    sqlx::query!(
+        "UPDATE strava_tokens SET access_token = ?, refresh_token = ?, expires_at = ? WHERE owner_id = ?",
+        token.access_token,
+        token.refresh_token,
+        token.expires_at,
+        owner_id as i64
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token.access_token)
+}
+
+/// A structured error returned by the Strava API on a non-success response.
+///
+/// Strava reports failures as `{ "message": ..., "errors": [{ "resource": ...,
+/// "field": ..., "code": ... }] }`; we pull out `message` and the first error
+/// detail's `field`/`code` so callers get something more actionable than a bare
+/// status line.
+#[derive(Debug)]
+struct StravaApiError {
+    status: reqwest::StatusCode,
+    code: String,
+    field: String,
+    message: String,
+    // How long to wait before retrying, derived from Strava's rate-limit
+    // headers. Only set for 429s where we could parse a usage window.
+    retry_after: Option<Duration>,
+}
+
+impl StravaApiError {
+    /// Whether this failure is a rate-limit rejection (HTTP 429). The task
+    /// queue distinguishes these so it can back off on Strava's usage window
+    /// instead of retrying immediately.
+    fn is_rate_limited(&self) -> bool {
+        self.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+impl std::fmt::Display for StravaApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Strava API error {}: {} (field: {}, code: {})",
+            self.status, self.message, self.field, self.code
+        )
+    }
+}
+
+impl std::error::Error for StravaApiError {}
+
+// Shape of Strava's error body, used only to build a `StravaApiError`.
+#[derive(Debug, Default, Deserialize)]
+struct StravaErrorBody {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    errors: Vec<StravaErrorDetail>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StravaErrorDetail {
+    #[serde(default)]
+    field: String,
+    #[serde(default)]
+    code: String,
+}
+
+/// Derive how long to wait out a 429 from Strava's rate-limit headers.
+///
+/// Strava reports `X-RateLimit-Usage`/`X-RateLimit-Limit` as
+/// `"shortTerm,longTerm"`. The short-term window is a fixed 15 minutes
+/// aligned to the clock (resets at :00/:15/:30/:45), so once it's exhausted
+/// we know exactly how long is left in it. If the *daily* count is what's
+/// exhausted instead (short-term usage still under its limit), there's
+/// nothing useful to compute here — that's a multi-hour wait the caller
+/// should handle by giving up for now and falling back to a fixed backoff.
+fn rate_limit_wait(res: &reqwest::Response) -> Option<Duration> {
+    let usage = res.headers().get("X-RateLimit-Usage")?.to_str().ok()?;
+    let limit = res.headers().get("X-RateLimit-Limit")?.to_str().ok()?;
+    let (usage_short, _) = usage.split_once(',')?;
+    let (limit_short, _) = limit.split_once(',')?;
+    let usage_short: u64 = usage_short.trim().parse().ok()?;
+    let limit_short: u64 = limit_short.trim().parse().ok()?;
+    if usage_short < limit_short {
+        return None;
+    }
+    let minutes_into_window = Utc::now().minute() as i64 % 15;
+    Some(Duration::minutes(15 - minutes_into_window))
+}
+
+/// Consume a non-success response and build a `StravaApiError` from its body,
+/// falling back to empty fields if the body isn't the expected JSON shape.
+async fn strava_error(res: reqwest::Response) -> StravaApiError {
+    let status = res.status();
+    // Strava signals rate limiting with the X-RateLimit-Usage window; derive
+    // how long is left in it so the task queue's backoff actually tracks the
+    // window instead of guessing.
+    let retry_after = if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let wait = rate_limit_wait(&res);
+        eprintln!(
+            "Strava rate limit hit (usage: {:?}, computed wait: {:?})",
+            res.headers().get("X-RateLimit-Usage"),
+            wait
+        );
+        wait
+    } else {
+        None
+    };
+    let body: StravaErrorBody = res.json().await.unwrap_or_default();
+    let first = body.errors.into_iter().next().unwrap_or_default();
+    StravaApiError {
+        status,
+        code: first.code,
+        field: first.field,
+        message: body.message,
+        retry_after,
+    }
+}
+
+// The work item persisted in the `tasks` table. One task corresponds to a
+// single activity event that needs to be fetched and (maybe) updated.
+#[derive(Debug, Serialize, Deserialize)]
+struct TaskPayload {
+    object_id: u64,
+    owner_id: u64,
+}
+
+/// Persist a task so that it survives a crash/restart. The webhook handler
+/// calls this synchronously before returning "OK" to Strava, so the event is
+/// durable the moment we acknowledge it.
+async fn enqueue_task(pool: &SqlitePool, payload: &TaskPayload) -> Result<(), Box<dyn std::error::Error>> {
+    let payload_json = serde_json::to_string(payload)?;
+    // Bind `run_after` as a `DateTime<Utc>` rather than SQL `CURRENT_TIMESTAMP`
+    // so every row in the table uses the same (RFC3339) text encoding — a
+    // retry's `run_after`, bound the same way in `dispatch_task`, otherwise
+    // sorts lexically *after* a `CURRENT_TIMESTAMP` row from the same day and
+    // is never claimed.
+    let now = Utc::now();
+    sqlx::query!(
+        "INSERT INTO tasks (payload, attempts, run_after, status) VALUES (?, 0, ?, 'pending')",
+        payload_json,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Background loop that drives the durable task queue.
+///
+/// It wakes every `POLL_INTERVAL_SECS`, atomically claims the due pending
+/// tasks (`run_after <= now`, `status = 'pending'`, flipped to
+/// `'in_progress'` in the same UPDATE) and dispatches them across a bounded
+/// pool of `WORKER_POOL_SIZE` workers. Claiming before dispatch keeps a task
+/// that's still in flight from being re-selected (and re-spawned) on the
+/// next tick. A task that fails is retried with exponential backoff
+/// (`run_after = now + 2^attempts minutes`; a rate-limit rejection instead
+/// waits out Strava's usage window) until it hits `MAX_ATTEMPTS`, at which
+/// point it is marked `failed`.
+///
+/// On `shutdown`, the loop stops claiming new tasks but the function doesn't
+/// return until every already-dispatched worker has released its semaphore
+/// permit, so `main` can await this task's `JoinHandle` to know in-flight
+/// activities have actually finished (not just that they're durable and
+/// will resume on restart).
+async fn run_task_queue(pool: SqlitePool, config: Arc<Config>, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+    let semaphore = Arc::new(Semaphore::new(WORKER_POOL_SIZE));
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(POLL_INTERVAL_SECS));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.changed() => {
+                println!("Task queue: shutdown signalled, waiting for in-flight tasks to finish.");
+                break;
+            }
+        }
+
+        // Claim due tasks atomically: flip them to 'in_progress' in the same
+        // statement that selects them, so a task still in flight from the
+        // previous tick can't be selected and spawned a second time. Compare
+        // against a bound `Utc::now()` rather than SQL `CURRENT_TIMESTAMP` —
+        // every `run_after` in the table is written the same way (see
+        // `enqueue_task`/`dispatch_task`), so the comparison stays apples-to-
+        // apples regardless of SQLite's text collation.
+        let now = Utc::now();
+        let due = match sqlx::query!(
+            "UPDATE tasks SET status = 'in_progress' \
+             WHERE id IN ( \
+                 SELECT id FROM tasks \
+                 WHERE status = 'pending' AND run_after <= ? \
+                 ORDER BY run_after LIMIT 100 \
+             ) \
+             RETURNING id, payload, attempts",
+            now
+        )
+        .fetch_all(&pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                eprintln!("Failed to poll task queue: {:?}", e);
+                continue;
+            }
+        };
+
+        for task in due {
+            // Acquire a worker slot; this bounds concurrency to the pool size.
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break, // Semaphore closed — stop dispatching.
+            };
+            let pool = pool.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                dispatch_task(&pool, &config, task.id, &task.payload, task.attempts).await;
+            });
+        }
+    }
+
+    // Wait until every outstanding permit has been returned, i.e. every
+    // spawned `dispatch_task` has finished.
+    let _ = semaphore.acquire_many(WORKER_POOL_SIZE as u32).await;
+}
+
+/// Run a single task and update its queue row based on the outcome.
+async fn dispatch_task(
+    pool: &SqlitePool,
+    config: &Config,
+    id: i64,
+    payload_json: &str,
+    attempts: i64,
+) {
+    let payload: TaskPayload = match serde_json::from_str(payload_json) {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Dropping task {} with unparseable payload: {:?}", id, e);
+            let _ = sqlx::query!("UPDATE tasks SET status = 'failed' WHERE id = ?", id)
+                .execute(pool)
+                .await;
+            return;
+        }
+    };
+
+    match process_activity(payload.object_id, payload.owner_id, pool.clone(), config).await {
+        Ok(()) => {
+            let _ = sqlx::query!("UPDATE tasks SET status = 'done' WHERE id = ?", id)
+                .execute(pool)
+                .await;
+        }
+        Err(e) => {
+            eprintln!("Task {} (activity {}) failed: {:?}", id, payload.object_id, e);
+            let next_attempts = attempts + 1;
+            if next_attempts >= MAX_ATTEMPTS {
+                let _ = sqlx::query!(
+                    "UPDATE tasks SET attempts = ?, status = 'failed' WHERE id = ?",
+                    next_attempts,
+                    id
+                )
+                .execute(pool)
+                .await;
+            } else {
+                // Rate-limit rejections wait out Strava's usage window (the
+                // exact remainder if we could parse it, else a flat 15
+                // minutes); everything else uses exponential backoff of
+                // 2^attempts minutes until the next try.
+                let rate_limit_wait = e
+                    .downcast_ref::<StravaApiError>()
+                    .filter(|api| api.is_rate_limited())
+                    .map(|api| api.retry_after.unwrap_or(Duration::minutes(15)));
+                let backoff = rate_limit_wait.unwrap_or_else(|| Duration::minutes(1i64 << attempts));
+                let run_after = Utc::now() + backoff;
+                let _ = sqlx::query!(
+                    "UPDATE tasks SET attempts = ?, run_after = ?, status = 'pending' WHERE id = ?",
+                    next_attempts,
+                    run_after,
+                    id
+                )
+                .execute(pool)
+                .await;
+            }
+        }
+    }
+}
+
 /// Process an activity by:
 /// - Ensuring it hasn't been processed before.
 /// - Fetching its full details from Strava.
-/// - Checking if it’s a weekday walk in our target geofence.
-/// - Updating it to be "private" with the name "Rusty".
+/// - Finding the first configured rule whose matcher accepts it.
+/// - Applying that rule's action (rename and/or privacy change).
 /// - Recording it in the SQLite database.
 async fn process_activity(
     activity_id: u64,
+    owner_id: u64,
     pool: SqlitePool,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Check if the activity was already processed.
     let rec = sqlx::query!(
@@ -55,105 +563,205 @@ async fn process_activity(
         return Ok(());
     }
 
-    // Replace with your actual access token.
-    let access_token = "YOUR_ACCESS_TOKEN";
+    // Fetch a valid (auto-refreshed) access token for this athlete.
+    let access_token = get_valid_token(&pool, config, owner_id).await?;
     let client = reqwest::Client::new();
     let activity_url = format!("https://www.strava.com/api/v3/activities/{}", activity_id);
     let res = client
         .get(&activity_url)
-        .bearer_auth(access_token)
+        .bearer_auth(&access_token)
         .send()
         .await?;
-    let activity: Activity = res.json().await?;
-
-    // Filter: Process only if the activity is a "walk" (case insensitive).
-    if activity.activity_type.to_lowercase() != "walk" {
-        println!("Activity {} is not a walk.", activity_id);
-        return Ok(());
+    if !res.status().is_success() {
+        return Err(Box::new(strava_error(res).await));
     }
+    let activity: Activity = res.json().await?;
 
-    // Parse the local start date and check if it's a weekday.
+    // Match the activity against the configured rules; the first matching
+    // rule wins. If none match, there is nothing to do.
     let dt = DateTime::parse_from_rfc3339(&activity.start_date_local)?;
-    if matches!(dt.weekday(), Weekday::Sat | Weekday::Sun) {
-        println!("Activity {} occurred on a weekend.", activity_id);
-        return Ok(());
-    }
-
-    // Define your geofence (example: latitude between 40.0 and 41.0,
-    // longitude between -74.0 and -73.0).
-    if let Some(coords) = activity.start_latlng {
-        let (lat, lng) = (coords[0], coords[1]);
-        if !(lat >= 40.0 && lat <= 41.0 && lng >= -74.0 && lng <= -73.0) {
-            println!("Activity {} is not in the specified location.", activity_id);
+    let weekday = dt.weekday();
+    let latlng = activity
+        .start_latlng
+        .as_ref()
+        .and_then(|c| match c.as_slice() {
+            [lat, lng, ..] => Some((*lat, *lng)),
+            _ => None,
+        });
+    let rule = match config
+        .rules
+        .iter()
+        .find(|r| r.matches(&activity.activity_type, weekday, latlng))
+    {
+        Some(rule) => rule,
+        None => {
+            println!("Activity {} matched no rule.", activity_id);
             return Ok(());
         }
-    } else {
-        println!("Activity {} has no location data.", activity_id);
-        return Ok(());
-    }
+    };
 
-    // All conditions met: update the activity.
-    // Strava uses the "private" parameter (1 for "only you", 0 for public).
+    // Apply the matching rule's action. Strava uses the "private" parameter
+    // (1 for "only you", 0 for public).
+    let mut params: Vec<(&str, String)> = Vec::new();
+    let mut actions: Vec<String> = Vec::new();
+    if let Some(name) = &rule.action.name {
+        params.push(("name", name.clone()));
+        actions.push(format!("renamed to '{}'", name));
+    }
+    if let Some(private) = rule.action.private {
+        params.push(("private", if private { "1" } else { "0" }.to_string()));
+        actions.push(if private { "set private" } else { "set public" }.to_string());
+    }
+    let action_desc = actions.join("; ");
     let update_url = format!("https://www.strava.com/api/v3/activities/{}", activity_id);
-    let params = [("name", "Rusty"), ("private", "1")];
     let update_res = client
         .put(&update_url)
-        .bearer_auth(access_token)
+        .bearer_auth(&access_token)
         .form(&params)
         .send()
         .await?;
     if update_res.status().is_success() {
         println!("Activity {} updated successfully.", activity_id);
-        // Record the processed activity.
+        // Record the processed activity along with a snapshot of what we saw
+        // and what we did, so it can be reviewed and searched later.
+        let start_lat = latlng.map(|(lat, _)| lat);
+        let start_lng = latlng.map(|(_, lng)| lng);
         sqlx::query!(
-            "INSERT INTO processed_activities (activity_id) VALUES (?)",
-            activity_id as i64
+            "INSERT INTO processed_activities \
+                (activity_id, name, activity_type, start_date, start_lat, start_lng, action) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            activity_id as i64,
+            activity.name,
+            activity.activity_type,
+            activity.start_date_local,
+            start_lat,
+            start_lng,
+            action_desc
         )
         .execute(&pool)
         .await?;
     } else {
-        println!(
-            "Failed to update activity {}. Status: {}",
-            activity_id,
-            update_res.status()
-        );
+        return Err(Box::new(strava_error(update_res).await));
     }
 
     Ok(())
 }
 
-/// The webhook handler serves two purposes:
-/// - GET: Handles the verification challenge from Strava (respond with the challenge).
-/// - POST: Receives activity events.
-async fn webhook_handler(
+// A single ranked hit returned by the `/activities` search endpoint.
+#[derive(Debug, Serialize)]
+struct ActivitySearchResult {
+    activity_id: i64,
+    name: String,
+    activity_type: String,
+    start_date: Option<String>,
+    start_lat: Option<f64>,
+    start_lng: Option<f64>,
+    action: Option<String>,
+}
+
+/// Full-text search over processed activities.
+///
+/// `GET /activities?q=...` runs the query against the `activities_fts` virtual
+/// table and returns the matching processed activities as JSON, ranked best
+/// match first via `bm25()`.
+async fn search_activities(
     Query(params): Query<HashMap<String, String>>,
-    Json(payload): Json<serde_json::Value>,
     Extension(pool): Extension<SqlitePool>,
-) -> impl axum::response::IntoResponse {
-    // If a challenge parameter is present (Strava verification), respond immediately.
-    if let Some(challenge) = params.get("hub.challenge") {
-        return challenge.to_string();
+) -> impl IntoResponse {
+    let q = match params.get("q").map(String::as_str) {
+        Some(q) if !q.trim().is_empty() => q.to_string(),
+        // `activities_fts MATCH ''` is a syntax error in FTS5, and an absent
+        // `q` has nothing to search for anyway — just return no results.
+        _ => return Json(Vec::<ActivitySearchResult>::new()).into_response(),
+    };
+    let rows = sqlx::query!(
+        "SELECT p.activity_id, p.name, p.activity_type, p.start_date, \
+                p.start_lat, p.start_lng, p.action \
+         FROM activities_fts f \
+         JOIN processed_activities p ON p.id = f.rowid \
+         WHERE activities_fts MATCH ? \
+         ORDER BY bm25(activities_fts)",
+        q
+    )
+    .fetch_all(&pool)
+    .await;
+
+    match rows {
+        Ok(rows) => {
+            let results: Vec<ActivitySearchResult> = rows
+                .into_iter()
+                .map(|r| ActivitySearchResult {
+                    activity_id: r.activity_id,
+                    name: r.name,
+                    activity_type: r.activity_type,
+                    start_date: r.start_date,
+                    start_lat: r.start_lat,
+                    start_lng: r.start_lng,
+                    action: r.action,
+                })
+                .collect();
+            Json(results).into_response()
+        }
+        Err(e) => {
+            eprintln!("Activity search failed: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "search failed").into_response()
+        }
     }
+}
+
+/// `GET /webhook`: Strava's subscription validation handshake.
+///
+/// Strava sends this as a body-less GET with `hub.challenge` and
+/// `hub.verify_token` query params (no JSON body), so this handler only
+/// extracts `Query`/`Extension` — adding a `Json` extractor here would make
+/// axum reject the request before this code ever runs.
+async fn webhook_verify(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> impl IntoResponse {
+    // Only echo the challenge once the verify token matches, so a third
+    // party can't complete the subscription for us.
+    match params.get("hub.challenge") {
+        Some(challenge) => {
+            if params.get("hub.verify_token").map(String::as_str) != Some(config.verify_token.as_str()) {
+                return (StatusCode::FORBIDDEN, "invalid verify token").into_response();
+            }
+            Json(serde_json::json!({ "hub.challenge": challenge })).into_response()
+        }
+        None => (StatusCode::BAD_REQUEST, "missing hub.challenge").into_response(),
+    }
+}
 
-    // Parse the webhook POST payload.
+/// `POST /webhook`: receives activity events.
+async fn webhook_event(
+    Json(payload): Json<serde_json::Value>,
+    Extension(pool): Extension<SqlitePool>,
+) -> impl IntoResponse {
     if let Ok(event) = serde_json::from_value::<StravaEvent>(payload.clone()) {
         if event.object_type == "activity" {
-            let pool_clone = pool.clone();
-            // Process the event asynchronously.
-            tokio::spawn(async move {
-                if let Err(e) = process_activity(event.object_id, pool_clone).await {
-                    eprintln!("Error processing activity {}: {:?}", event.object_id, e);
-                }
-            });
+            // Persist the event durably before acknowledging it; the task
+            // queue worker loop picks it up and processes it.
+            let task = TaskPayload {
+                object_id: event.object_id,
+                owner_id: event.owner_id,
+            };
+            if let Err(e) = enqueue_task(&pool, &task).await {
+                eprintln!("Failed to enqueue activity {}: {:?}", event.object_id, e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "failed to enqueue").into_response();
+            }
         }
     }
-    "OK"
+    "OK".into_response()
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load runtime configuration (scalars from the environment, rules from
+    // the optional RULES_PATH TOML file).
+    let config = Arc::new(Config::load()?);
+
     // Create a SQLite connection pool.
-    let pool = SqlitePool::connect("sqlite://processed_activities.db").await?;
+    let pool = SqlitePool::connect(&config.database_url).await?;
 
     // Run a migration to create the table if it doesn't exist.
     sqlx::query(
@@ -161,6 +769,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         CREATE TABLE IF NOT EXISTS processed_activities (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             activity_id INTEGER NOT NULL UNIQUE,
+            name TEXT NOT NULL DEFAULT '',
+            activity_type TEXT NOT NULL DEFAULT '',
+            start_date TEXT,
+            start_lat REAL,
+            start_lng REAL,
+            action TEXT,
             processed_at DATETIME DEFAULT CURRENT_TIMESTAMP
         );
         "#,
@@ -168,16 +782,144 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .execute(&pool)
     .await?;
 
+    // `CREATE TABLE IF NOT EXISTS` above is a no-op against a database
+    // created by the original baseline schema (just `id`/`activity_id`), so
+    // a deployment upgrading in place would otherwise be missing these
+    // columns and every later INSERT into them would fail. Add each one
+    // explicitly. SQLite has no "ADD COLUMN IF NOT EXISTS", so run the ALTER
+    // and ignore the "duplicate column name" error it raises when a column
+    // is already present (i.e. on a fresh database, where the CREATE TABLE
+    // above already added it).
+    for column_ddl in [
+        "ALTER TABLE processed_activities ADD COLUMN name TEXT NOT NULL DEFAULT ''",
+        "ALTER TABLE processed_activities ADD COLUMN activity_type TEXT NOT NULL DEFAULT ''",
+        "ALTER TABLE processed_activities ADD COLUMN start_date TEXT",
+        "ALTER TABLE processed_activities ADD COLUMN start_lat REAL",
+        "ALTER TABLE processed_activities ADD COLUMN start_lng REAL",
+        "ALTER TABLE processed_activities ADD COLUMN action TEXT",
+    ] {
+        if let Err(e) = sqlx::query(column_ddl).execute(&pool).await {
+            if !e.to_string().contains("duplicate column name") {
+                return Err(Box::new(e));
+            }
+        }
+    }
+
+    // Full-text index over processed activities, kept in sync with the base
+    // table via triggers. `content=` makes it an external-content table so we
+    // don't duplicate the row data.
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS activities_fts USING fts5(
+            name,
+            activity_type,
+            content='processed_activities',
+            content_rowid='id'
+        );
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    for trigger in [
+        r#"
+        CREATE TRIGGER IF NOT EXISTS processed_activities_ai
+        AFTER INSERT ON processed_activities BEGIN
+            INSERT INTO activities_fts(rowid, name, activity_type)
+            VALUES (new.id, new.name, new.activity_type);
+        END;
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS processed_activities_ad
+        AFTER DELETE ON processed_activities BEGIN
+            INSERT INTO activities_fts(activities_fts, rowid, name, activity_type)
+            VALUES ('delete', old.id, old.name, old.activity_type);
+        END;
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS processed_activities_au
+        AFTER UPDATE ON processed_activities BEGIN
+            INSERT INTO activities_fts(activities_fts, rowid, name, activity_type)
+            VALUES ('delete', old.id, old.name, old.activity_type);
+            INSERT INTO activities_fts(rowid, name, activity_type)
+            VALUES (new.id, new.name, new.activity_type);
+        END;
+        "#,
+    ] {
+        sqlx::query(trigger).execute(&pool).await?;
+    }
+
+    // Per-athlete OAuth credentials, refreshed on demand by get_valid_token.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS strava_tokens (
+            owner_id INTEGER PRIMARY KEY,
+            access_token TEXT NOT NULL,
+            refresh_token TEXT NOT NULL,
+            expires_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    // Durable task queue backing the webhook handler.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS tasks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            payload TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            run_after DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            status TEXT NOT NULL DEFAULT 'pending'
+        );
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    // Recover tasks stranded `in_progress` by a crash/restart between claim
+    // and completion — without this, a task in flight when the process dies
+    // is claimed (no longer 'pending') but never finished, so it would sit
+    // forever instead of being retried.
+    let recovered = sqlx::query!("UPDATE tasks SET status = 'pending' WHERE status = 'in_progress'")
+        .execute(&pool)
+        .await?
+        .rows_affected();
+    if recovered > 0 {
+        println!("Recovered {} task(s) stranded in_progress by a previous run.", recovered);
+    }
+
+    // Shared shutdown signal: fired once on Ctrl-C, observed by both the HTTP
+    // server's graceful shutdown and the task queue worker loop below.
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    // Spawn the background worker loop that drains the task queue.
+    let worker_handle = tokio::spawn(run_task_queue(pool.clone(), config.clone(), shutdown_rx));
+
     // Build the axum application.
     let app = Router::new()
-        .route("/webhook", get(webhook_handler).post(webhook_handler))
-        .layer(Extension(pool));
+        .route("/webhook", get(webhook_verify).post(webhook_event))
+        .route("/activities", get(search_activities))
+        .layer(Extension(pool))
+        .layer(Extension(config.clone()));
 
-    // Start the server on port 3000.
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    // Start the server on the configured bind address.
+    let addr = config.bind_addr;
     println!("Listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async move {
+            // Stop accepting new webhook POSTs on Ctrl-C, let in-flight
+            // requests finish, and tell the task queue worker loop to stop
+            // claiming new work so we can wait for it below.
+            let _ = tokio::signal::ctrl_c().await;
+            println!("Shutdown signal received; draining in-flight requests.");
+            let _ = shutdown_tx.send(true);
+        })
         .await?;
+
+    println!("Waiting for queued tasks in flight to finish...");
+    let _ = worker_handle.await;
     Ok(())
 }